@@ -1,14 +1,251 @@
 #[macro_use]
 extern crate cfg_if;
 
+use std::collections::HashMap;
 use std::env;
-use std::fs::{read_dir, Metadata, create_dir, remove_dir_all};
-use std::io::{self, Read, Write};
+use std::fs::{read_dir, remove_file, File, Metadata, OpenOptions, create_dir, remove_dir_all};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
 use std::path::{Path, PathBuf};
 use std::str::{self, FromStr};
+use std::sync::{Arc, Mutex};
 use std::thread;
 
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+/// A stream that can be either a plain `TcpStream` or one wrapped in TLS,
+/// so the control and data channels can be upgraded independently once
+/// `AUTH TLS` / `PROT P` are negotiated.
+trait ReadWrite: Read + Write + Send {}
+impl<T: Read + Write + Send> ReadWrite for T {}
+
+/// `password_hash` is `sha256(salt ++ password)`, hex-encoded; `salt` is a
+/// per-user random string generated when the account is provisioned so two
+/// users with the same password don't share a hash (no rainbow-table reuse).
+#[derive(Debug, Clone, Deserialize)]
+struct UserConfig {
+    password_hash: String,
+    #[serde(default)]
+    salt: String,
+    #[serde(default)]
+    root: Option<PathBuf>,
+}
+
+/// Loaded once at startup from `credentials.toml`: a username -> config map
+/// plus an `anonymous` toggle, so each account can be authenticated and
+/// chrooted into its own subtree via `Client::complete_path`.
+#[derive(Debug, Clone, Default, Deserialize)]
+struct CredentialStore {
+    #[serde(default)]
+    anonymous: bool,
+    #[serde(default)]
+    users: HashMap<String, UserConfig>,
+}
+
+impl CredentialStore {
+    fn load(path: &Path) -> CredentialStore {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| toml::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    /// Returns the absolute per-user root on success, `None` on bad credentials.
+    fn verify(&self, username: &str, password: &str, server_root: &Path) -> Option<PathBuf> {
+        if username == "anonymous" && self.anonymous {
+            return Some(server_root.to_path_buf());
+        }
+        let user = self.users.get(username)?;
+        let expected = hash_password(&user.salt, password);
+        let matches: bool = expected.as_bytes().ct_eq(user.password_hash.as_bytes()).into();
+        if !matches {
+            return None;
+        }
+        Some(match &user.root {
+            Some(root) => server_root.join(if root.has_root() {
+                root.iter().skip(1).collect()
+            } else {
+                root.clone()
+            }),
+            None => server_root.to_path_buf(),
+        })
+    }
+}
+
+/// Hashes in constant time with respect to the password's content (the
+/// comparison against the stored hash is what must stay constant-time, done
+/// via `ConstantTimeEq` in `CredentialStore::verify`).
+fn hash_password(salt: &str, password: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(password.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// How much detail `AuditLog` writes out. `Basic` records connects,
+/// disconnects, and command outcomes; `Verbose` additionally records every
+/// parsed command as it's received, before its outcome is known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verbosity {
+    Basic,
+    Verbose,
+}
+
+impl Verbosity {
+    fn from_env() -> Verbosity {
+        match env::var("SYN_FTP_LOG_LEVEL") {
+            Ok(level) if level.eq_ignore_ascii_case("verbose") => Verbosity::Verbose,
+            _ => Verbosity::Basic,
+        }
+    }
+}
+
+fn timestamp() -> String {
+    let tm = time::now_utc();
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+fn json_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Structured, append-only session audit trail: one newline-delimited JSON
+/// record per connect, command, and disconnect, shared by every client
+/// thread behind a single `Mutex`'d writer so interleaved stdout lines
+/// from `println!` don't get jumbled between concurrent connections.
+struct AuditLog {
+    writer: Mutex<File>,
+    level: Verbosity,
+}
+
+impl AuditLog {
+    fn open(path: &Path, level: Verbosity) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            writer: Mutex::new(file),
+            level,
+        })
+    }
+
+    fn write_line(&self, line: String) {
+        if let Ok(mut writer) = self.writer.lock() {
+            let _ = writer.write_all(line.as_bytes());
+            let _ = writer.flush();
+        }
+    }
+
+    fn log_connect(&self, peer: SocketAddr) {
+        self.write_line(format!(
+            "{{\"ts\":\"{}\",\"peer\":\"{}\",\"event\":\"connect\"}}\n",
+            timestamp(),
+            peer
+        ));
+    }
+
+    fn log_disconnect(&self, peer: SocketAddr) {
+        self.write_line(format!(
+            "{{\"ts\":\"{}\",\"peer\":\"{}\",\"event\":\"disconnect\"}}\n",
+            timestamp(),
+            peer
+        ));
+    }
+
+    fn log_command(&self, peer: SocketAddr, min_level: Verbosity, command: &str) {
+        if min_level == Verbosity::Verbose && self.level != Verbosity::Verbose {
+            return;
+        }
+        self.write_line(format!(
+            "{{\"ts\":\"{}\",\"peer\":\"{}\",\"event\":\"command\",\"command\":\"{}\"}}\n",
+            timestamp(),
+            peer,
+            json_escape(command)
+        ));
+    }
+
+    fn log_result(&self, peer: SocketAddr, command: &str, code: ResultCode, bytes: u64) {
+        self.write_line(format!(
+            "{{\"ts\":\"{}\",\"peer\":\"{}\",\"event\":\"result\",\"command\":\"{}\",\"code\":{},\"bytes\":{}}}\n",
+            timestamp(),
+            peer,
+            json_escape(command),
+            code as u32,
+            bytes
+        ));
+    }
+}
+
+/// Passive-mode port range and the externally reachable address to
+/// advertise in `PASV`/`EPSV` replies, both configurable since a fixed
+/// `127,0,0,1` only ever works for a single local client.
+struct NetConfig {
+    pasv_port_range: (u16, u16),
+    public_ip: Ipv4Addr,
+}
+
+impl NetConfig {
+    fn from_env() -> NetConfig {
+        let pasv_port_range = env::var("SYN_FTP_PASV_PORTS")
+            .ok()
+            .and_then(|range| parse_port_range(&range))
+            .unwrap_or((50000, 50100));
+        let public_ip = env::var("SYN_FTP_PUBLIC_IP")
+            .ok()
+            .and_then(|ip| ip.parse().ok())
+            .unwrap_or_else(|| Ipv4Addr::new(127, 0, 0, 1));
+        NetConfig {
+            pasv_port_range,
+            public_ip,
+        }
+    }
+}
+
+fn parse_port_range(s: &str) -> Option<(u16, u16)> {
+    let mut parts = s.splitn(2, '-');
+    let low: u16 = parts.next()?.trim().parse().ok()?;
+    let high: u16 = parts.next()?.trim().parse().ok()?;
+    if low <= high {
+        Some((low, high))
+    } else {
+        None
+    }
+}
+
+/// Binds the first free port in `range`, since passive mode needs a
+/// predictable, firewall-friendly range rather than a single fixed port.
+fn bind_passive_listener(range: (u16, u16)) -> io::Result<TcpListener> {
+    for port in range.0..=range.1 {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+        if let Ok(listener) = TcpListener::bind(addr) {
+            return Ok(listener);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "no free port in passive range",
+    ))
+}
+
+/// Seeks `file` to `offset` for a `REST`-resumed transfer. Returns `false`
+/// when `offset` lands past the end of the file, which the caller turns
+/// into a `501` rather than silently clamping or zero-filling.
+fn seek_to_offset(file: &mut File, offset: u64) -> bool {
+    match file.metadata() {
+        Ok(meta) if offset <= meta.len() => file.seek(SeekFrom::Start(offset)).is_ok(),
+        _ => false,
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u32)]
 #[allow(dead_code)]
@@ -30,6 +267,7 @@ enum ResultCode {
     ClosingDataConnection = 226,
     EnteringPassiveMode = 227,
     UserLoggedIn = 230,
+    SecurityMechanismAccepted = 234,
     RequestedFileActionOkay = 250,
     PATHNAMECreated = 257,
     UserNameOkayNeedPassword = 331,
@@ -59,6 +297,7 @@ enum Command {
     Auth,
     Syst,
     User(String),
+    Pass(String),
     Noop,
     Pwd,
     Type,
@@ -68,6 +307,17 @@ enum Command {
     CdUp,
     Mkd(PathBuf),
     Rmd(PathBuf),
+    Retr(Option<PathBuf>),
+    Stor(Option<PathBuf>),
+    Dele(Option<PathBuf>),
+    Pbsz(u64),
+    Prot(String),
+    Port(Option<SocketAddr>),
+    Eprt(Option<SocketAddr>),
+    Epsv,
+    Rest(u64),
+    Mlsd(PathBuf),
+    Mlst(PathBuf),
     Unknown(String),
 }
 
@@ -77,6 +327,7 @@ impl AsRef<str> for Command {
             Command::Auth => "AUTH",
             Command::Syst => "SYST",
             Command::User(_) => "USER",
+            Command::Pass(_) => "PASS",
             Command::Noop => "NOOP",
             Command::Pwd => "PWD",
             Command::Type => "TYPE",
@@ -86,6 +337,17 @@ impl AsRef<str> for Command {
             Command::CdUp => "CDUP",
             Command::Mkd(_) => "MKD",
             Command::Rmd(_) => "RMD",
+            Command::Retr(_) => "RETR",
+            Command::Stor(_) => "STOR",
+            Command::Dele(_) => "DELE",
+            Command::Pbsz(_) => "PBSZ",
+            Command::Prot(_) => "PROT",
+            Command::Port(_) => "PORT",
+            Command::Eprt(_) => "EPRT",
+            Command::Epsv => "EPSV",
+            Command::Rest(_) => "REST",
+            Command::Mlsd(_) => "MLSD",
+            Command::Mlst(_) => "MLST",
             Command::Unknown(_) => "UNKN",
         }
     }
@@ -106,6 +368,10 @@ impl Command {
                 })
                 .unwrap_or_default(),
             ),
+            b"PASS" => Command::Pass(
+                data.map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+                    .unwrap_or_default(),
+            ),
             b"NOOP" => Command::Noop,
             b"PWD" => Command::Pwd,
             b"TYPE" => Command::Type,
@@ -126,12 +392,86 @@ impl Command {
             .unwrap()),
             b"RMD" => Command::Rmd(data.map(|bytes| Path::new(str::from_utf8(bytes).unwrap()).to_path_buf())
             .unwrap()),
+            b"RETR" => Command::Retr(
+                data.and_then(|bytes| str::from_utf8(bytes).ok())
+                    .map(|s| Path::new(s).to_path_buf()),
+            ),
+            b"STOR" => Command::Stor(
+                data.and_then(|bytes| str::from_utf8(bytes).ok())
+                    .map(|s| Path::new(s).to_path_buf()),
+            ),
+            b"DELE" => Command::Dele(
+                data.and_then(|bytes| str::from_utf8(bytes).ok())
+                    .map(|s| Path::new(s).to_path_buf()),
+            ),
+            b"PBSZ" => Command::Pbsz(
+                data.and_then(|bytes| str::from_utf8(bytes).ok())
+                    .and_then(|size| size.parse().ok())
+                    .unwrap_or(0),
+            ),
+            b"PROT" => Command::Prot(
+                data.map(|bytes| String::from_utf8_lossy(bytes).to_uppercase())
+                    .unwrap_or_default(),
+            ),
+            b"PORT" => Command::Port(parse_port_command(data.unwrap_or(&[]))),
+            b"EPRT" => Command::Eprt(parse_eprt_command(data.unwrap_or(&[]))),
+            b"EPSV" => Command::Epsv,
+            b"REST" => Command::Rest(
+                data.and_then(|bytes| str::from_utf8(bytes).ok())
+                    .and_then(|offset| offset.trim().parse().ok())
+                    .unwrap_or(0),
+            ),
+            b"MLSD" => Command::Mlsd(
+                if let Some(path) = data {
+                    Path::new(str::from_utf8(path).unwrap()).to_path_buf()
+                } else {
+                    PathBuf::from_str(".").unwrap()
+                }
+            ),
+            b"MLST" => Command::Mlst(
+                if let Some(path) = data {
+                    Path::new(str::from_utf8(path).unwrap()).to_path_buf()
+                } else {
+                    PathBuf::from_str(".").unwrap()
+                }
+            ),
             s => Command::Unknown(str::from_utf8(s).unwrap_or("").to_owned()),
         };
         Ok(command)
     }
 }
 
+/// Parses the classic `PORT h1,h2,h3,h4,p1,p2` argument into the IPv4
+/// address/port the client wants the server to dial back to.
+fn parse_port_command(data: &[u8]) -> Option<SocketAddr> {
+    let parts: Vec<u16> = str::from_utf8(data)
+        .ok()?
+        .trim()
+        .split(',')
+        .map(|part| part.parse().ok())
+        .collect::<Option<Vec<u16>>>()?;
+    if parts.len() != 6 {
+        return None;
+    }
+    let ip = Ipv4Addr::new(parts[0] as u8, parts[1] as u8, parts[2] as u8, parts[3] as u8);
+    let port = (parts[4] << 8) | parts[5];
+    Some(SocketAddr::new(IpAddr::V4(ip), port))
+}
+
+/// Parses the `EPRT |proto|addr|port|` argument (RFC 2428), which also
+/// covers IPv6 active-mode targets that classic `PORT` cannot express.
+fn parse_eprt_command(data: &[u8]) -> Option<SocketAddr> {
+    let s = str::from_utf8(data).ok()?;
+    let delim = s.chars().next()?;
+    let parts: Vec<&str> = s.trim_matches(delim).split(delim).collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    let addr: IpAddr = parts[1].parse().ok()?;
+    let port: u16 = parts[2].parse().ok()?;
+    Some(SocketAddr::new(addr, port))
+}
+
 cfg_if! {
     if #[cfg(windows)] {
         fn get_file_info(meta: &Metadata) -> (time::Tm, u64) {
@@ -149,19 +489,85 @@ cfg_if! {
 #[allow(dead_code)]
 struct Client {
     cwd: PathBuf,
-    stream: TcpStream,
+    stream: Box<dyn ReadWrite>,
+    control_sock: TcpStream,
     name: Option<String>,
-    data_writer: Option<TcpStream>,
+    data_writer: Option<Box<dyn ReadWrite>>,
+    tls_config: Option<Arc<ServerConfig>>,
+    tls_active: bool,
+    protected: bool,
+    credentials: Arc<CredentialStore>,
+    server_root: PathBuf,
+    root: PathBuf,
+    pending_user: Option<String>,
+    authenticated: bool,
+    audit: Arc<AuditLog>,
+    peer: SocketAddr,
+    net_config: Arc<NetConfig>,
+    active_target: Option<SocketAddr>,
+    restart_offset: Option<u64>,
 }
 
 impl Client {
-    fn new(stream: TcpStream) -> Client {
+    fn new(
+        stream: TcpStream,
+        tls_config: Option<Arc<ServerConfig>>,
+        credentials: Arc<CredentialStore>,
+        server_root: PathBuf,
+        audit: Arc<AuditLog>,
+        net_config: Arc<NetConfig>,
+    ) -> Client {
+        let control_sock = stream.try_clone().expect("failed to clone control socket");
+        let peer = control_sock
+            .peer_addr()
+            .unwrap_or_else(|_| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0));
         Client {
             cwd: PathBuf::from("/"),
-            stream: stream,
+            stream: Box::new(stream),
+            control_sock,
             name: None,
             data_writer: None,
+            tls_config,
+            tls_active: false,
+            protected: false,
+            credentials,
+            root: server_root.clone(),
+            server_root,
+            pending_user: None,
+            authenticated: false,
+            audit,
+            peer,
+            net_config,
+            active_target: None,
+            restart_offset: None,
+        }
+    }
+
+    /// Dials out to the client's `PORT`/`EPRT` target when active mode is
+    /// in effect and no passive connection has been accepted yet, so
+    /// `LIST`/`RETR`/`STOR` can use either transport transparently.
+    fn ensure_data_connection(&mut self) {
+        if self.data_writer.is_some() {
+            return;
+        }
+        if let Some(addr) = self.active_target.take() {
+            if let Ok(sock) = TcpStream::connect(addr) {
+                self.data_writer = Some(self.wrap_data_stream(sock));
+            }
+        }
+    }
+
+    /// Wraps a freshly accepted/dialed data-connection socket in TLS when
+    /// `PROT P` is in effect, otherwise returns it unchanged.
+    fn wrap_data_stream(&self, sock: TcpStream) -> Box<dyn ReadWrite> {
+        if self.protected {
+            if let Some(config) = &self.tls_config {
+                let conn = rustls::ServerConnection::new(config.clone())
+                    .expect("invalid TLS configuration");
+                return Box::new(rustls::StreamOwned::new(conn, sock));
+            }
         }
+        Box::new(sock)
     }
 
     fn complete_path(&self, path: PathBuf, server_root: &PathBuf) -> Result<PathBuf, io::Error> {
@@ -180,8 +586,16 @@ impl Client {
         dir
     }
 
-    fn cwd(&mut self, directory: PathBuf) {
-        let server_root = env::current_dir().unwrap();
+    fn complete_path_for_write(&self, path: PathBuf, server_root: &PathBuf) -> Result<PathBuf, io::Error> {
+        let parent = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+        let filename = path.file_name().ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let mut dir = self.complete_path(parent, server_root)?;
+        dir.push(filename);
+        Ok(dir)
+    }
+
+    fn cwd(&mut self, directory: PathBuf) -> ResultCode {
+        let server_root = self.root.clone();
         let path = self.cwd.join(&directory);
         if let Ok(dir) = self.complete_path(path, &server_root) {
             if let Ok(prefix) = dir.strip_prefix(&server_root).map(|p| p.to_path_buf()) {
@@ -190,13 +604,12 @@ impl Client {
                 } else {
                     self.cwd = prefix
                 }
-                println!("current cwd: {}", self.cwd.to_str().unwrap());
                 send_cmd(
                     &mut self.stream,
                     ResultCode::Ok,
                     &format!("Directory changed to \"{}\"", directory.display()),
                 );
-                return;
+                return ResultCode::Ok;
             }
         }
         send_cmd(
@@ -204,10 +617,11 @@ impl Client {
             ResultCode::FileNotFound,
             "No such file or directory",
         );
+        ResultCode::FileNotFound
     }
 
-    fn mkd(&mut self, path: PathBuf) {
-        let server_root = env::current_dir().unwrap();
+    fn mkd(&mut self, path: PathBuf) -> ResultCode {
+        let server_root = self.root.clone();
         let path = self.cwd.join(&path);
         if let Some(parent) = path.parent().map(|p| p.to_path_buf()) {
             if let Ok(mut dir) = self.complete_path(parent, &server_root) {
@@ -216,52 +630,259 @@ impl Client {
                         dir.push(filename);
                         if create_dir(dir).is_ok() {
                             send_cmd(&mut self.stream, ResultCode::PATHNAMECreated, "Folder successfully created!");
-                            return
+                            return ResultCode::PATHNAMECreated;
                         }
                     }
                 }
             }
         }
         send_cmd(&mut self.stream, ResultCode::FileNotFound, "Cound't create folder");
+        ResultCode::FileNotFound
     }
 
-    fn rmd(&mut self, path: PathBuf) {
-        let server_root = env::current_dir().unwrap();
+    fn rmd(&mut self, path: PathBuf) -> ResultCode {
+        let server_root = self.root.clone();
         if let Ok(path) = self.complete_path(path, &server_root) {
             if remove_dir_all(path).is_ok() {
                 send_cmd(&mut self.stream, ResultCode::RequestedFileActionOkay, "Folder successfully removed!");
-                return
+                return ResultCode::RequestedFileActionOkay;
             }
         }
         send_cmd(&mut self.stream, ResultCode::FileNotFound, "Coundn't remove folder!");
+        ResultCode::FileNotFound
+    }
+
+    fn retr(&mut self, path: PathBuf) -> (ResultCode, u64) {
+        let server_root = self.root.clone();
+        let path = self.cwd.join(&path);
+        let real_path = self.complete_path(path, &server_root);
+
+        let offset = self.restart_offset.take();
+
+        self.ensure_data_connection();
+        let mut data_writer = match self.data_writer.take() {
+            Some(stream) => stream,
+            None => {
+                send_cmd(
+                    &mut self.stream,
+                    ResultCode::ConnectionClosed,
+                    "No opened data connection",
+                );
+                return (ResultCode::ConnectionClosed, 0);
+            }
+        };
+
+        let result = real_path.and_then(File::open);
+        match result {
+            Ok(mut file) => {
+                if let Some(offset) = offset {
+                    if !seek_to_offset(&mut file, offset) {
+                        send_cmd(
+                            &mut self.stream,
+                            ResultCode::InvalidParameterOrArgument,
+                            "Restart position beyond end of file",
+                        );
+                        return (ResultCode::InvalidParameterOrArgument, 0);
+                    }
+                }
+                send_cmd(&mut self.stream, ResultCode::FileStatusOk, "Starting to send file...");
+                match io::copy(&mut file, &mut data_writer) {
+                    Ok(bytes) => {
+                        send_cmd(&mut self.stream, ResultCode::ClosingDataConnection, "Transfer done");
+                        (ResultCode::ClosingDataConnection, bytes)
+                    }
+                    Err(_) => {
+                        send_cmd(&mut self.stream, ResultCode::LocalErrorInProcessing, "Failed to send file");
+                        (ResultCode::LocalErrorInProcessing, 0)
+                    }
+                }
+            }
+            Err(_) => {
+                send_cmd(&mut self.stream, ResultCode::FileNotFound, "No such file or directory");
+                (ResultCode::FileNotFound, 0)
+            }
+        }
+    }
+
+    fn stor(&mut self, path: PathBuf) -> (ResultCode, u64) {
+        let server_root = self.root.clone();
+        let path = self.cwd.join(&path);
+        let real_path = self.complete_path_for_write(path, &server_root);
+
+        let offset = self.restart_offset.take();
+
+        self.ensure_data_connection();
+        let mut data_writer = match self.data_writer.take() {
+            Some(stream) => stream,
+            None => {
+                send_cmd(
+                    &mut self.stream,
+                    ResultCode::ConnectionClosed,
+                    "No opened data connection",
+                );
+                return (ResultCode::ConnectionClosed, 0);
+            }
+        };
+
+        let mut open_opts = OpenOptions::new();
+        open_opts.write(true).create(true).truncate(offset.is_none());
+        let result = real_path.and_then(|p| open_opts.open(p));
+        match result {
+            Ok(mut file) => {
+                if let Some(offset) = offset {
+                    if !seek_to_offset(&mut file, offset) {
+                        send_cmd(
+                            &mut self.stream,
+                            ResultCode::InvalidParameterOrArgument,
+                            "Restart position beyond end of file",
+                        );
+                        return (ResultCode::InvalidParameterOrArgument, 0);
+                    }
+                }
+                send_cmd(&mut self.stream, ResultCode::FileStatusOk, "Starting to receive file...");
+                match io::copy(&mut data_writer, &mut file) {
+                    Ok(bytes) => {
+                        send_cmd(&mut self.stream, ResultCode::ClosingDataConnection, "Transfer done");
+                        (ResultCode::ClosingDataConnection, bytes)
+                    }
+                    Err(_) => {
+                        send_cmd(&mut self.stream, ResultCode::LocalErrorInProcessing, "Failed to receive file");
+                        (ResultCode::LocalErrorInProcessing, 0)
+                    }
+                }
+            }
+            Err(_) => {
+                send_cmd(&mut self.stream, ResultCode::FileNotFound, "Couldn't create file");
+                (ResultCode::FileNotFound, 0)
+            }
+        }
+    }
+
+    fn dele(&mut self, path: PathBuf) -> ResultCode {
+        let server_root = self.root.clone();
+        let path = self.cwd.join(&path);
+        if let Ok(path) = self.complete_path(path, &server_root) {
+            if remove_file(path).is_ok() {
+                send_cmd(&mut self.stream, ResultCode::RequestedFileActionOkay, "File successfully removed!");
+                return ResultCode::RequestedFileActionOkay;
+            }
+        }
+        send_cmd(&mut self.stream, ResultCode::FileNotFound, "Couldn't remove file!");
+        ResultCode::FileNotFound
     }
 
     fn handle_cmd(&mut self, cmd: Command) {
-        println!("====> {:?}", cmd);
-        match cmd {
-            Command::Auth => send_cmd(
+        let summary = if let Command::Pass(_) = cmd {
+            "Pass(\"***\")".to_owned()
+        } else {
+            format!("{:?}", cmd)
+        };
+        self.audit.log_command(self.peer, Verbosity::Verbose, &summary);
+
+        if !self.authenticated && requires_login(&cmd) {
+            send_cmd(
                 &mut self.stream,
-                ResultCode::CommandNotImplemented,
-                "Not implemented",
-            ),
-            Command::Syst => send_cmd(&mut self.stream, ResultCode::Ok, "I won't tell"),
+                ResultCode::NotLoggedIn,
+                "Please login with USER and PASS",
+            );
+            self.audit.log_result(self.peer, &summary, ResultCode::NotLoggedIn, 0);
+            return;
+        }
+
+        let (code, bytes) = match cmd {
+            Command::Auth => {
+                if self.tls_active {
+                    send_cmd(
+                        &mut self.stream,
+                        ResultCode::BadSequenceOfCommands,
+                        "TLS already active",
+                    );
+                    (ResultCode::BadSequenceOfCommands, 0)
+                } else if let Some(config) = self.tls_config.clone() {
+                    send_cmd(
+                        &mut self.stream,
+                        ResultCode::SecurityMechanismAccepted,
+                        "AUTH TLS successful",
+                    );
+                    let sock = self
+                        .control_sock
+                        .try_clone()
+                        .expect("failed to clone control socket");
+                    let conn = rustls::ServerConnection::new(config)
+                        .expect("invalid TLS configuration");
+                    self.stream = Box::new(rustls::StreamOwned::new(conn, sock));
+                    self.tls_active = true;
+                    (ResultCode::SecurityMechanismAccepted, 0)
+                } else {
+                    send_cmd(
+                        &mut self.stream,
+                        ResultCode::CommandNotImplemented,
+                        "Not implemented",
+                    );
+                    (ResultCode::CommandNotImplemented, 0)
+                }
+            }
+            Command::Syst => {
+                send_cmd(&mut self.stream, ResultCode::Ok, "I won't tell");
+                (ResultCode::Ok, 0)
+            }
             Command::User(username) => {
                 if username.is_empty() {
                     send_cmd(
                         &mut self.stream,
                         ResultCode::InvalidParameterOrArgument,
                         "Invalid username",
-                    )
+                    );
+                    (ResultCode::InvalidParameterOrArgument, 0)
                 } else {
-                    self.name = Some(username.to_owned());
+                    self.authenticated = false;
+                    self.pending_user = Some(username.to_owned());
+                    send_cmd(
+                        &mut self.stream,
+                        ResultCode::UserNameOkayNeedPassword,
+                        &format!("Please specify the password for {}", username),
+                    );
+                    (ResultCode::UserNameOkayNeedPassword, 0)
+                }
+            }
+            Command::Pass(password) => match self.pending_user.take() {
+                Some(username) => {
+                    match self.credentials.verify(&username, &password, &self.server_root) {
+                        Some(root) => {
+                            self.name = Some(username.clone());
+                            self.root = root;
+                            self.cwd = PathBuf::from("/");
+                            self.authenticated = true;
+                            send_cmd(
+                                &mut self.stream,
+                                ResultCode::UserLoggedIn,
+                                &format!("Welcome {}!", username),
+                            );
+                            (ResultCode::UserLoggedIn, 0)
+                        }
+                        None => {
+                            send_cmd(
+                                &mut self.stream,
+                                ResultCode::NotLoggedIn,
+                                "Login incorrect",
+                            );
+                            (ResultCode::NotLoggedIn, 0)
+                        }
+                    }
+                }
+                None => {
                     send_cmd(
                         &mut self.stream,
-                        ResultCode::UserLoggedIn,
-                        &format!("Welcome {}!", username),
+                        ResultCode::BadSequenceOfCommands,
+                        "Login with USER first",
                     );
+                    (ResultCode::BadSequenceOfCommands, 0)
                 }
+            },
+            Command::Noop => {
+                send_cmd(&mut self.stream, ResultCode::Ok, "Doing nothing...");
+                (ResultCode::Ok, 0)
             }
-            Command::Noop => send_cmd(&mut self.stream, ResultCode::Ok, "Doing nothing..."),
             Command::Pwd => {
                 let msg = format!("{}", self.cwd.to_str().unwrap_or(""));
                 if !msg.is_empty() {
@@ -270,55 +891,164 @@ impl Client {
                         &mut self.stream,
                         ResultCode::PATHNAMECreated,
                         message.as_str(),
-                    )
+                    );
+                    (ResultCode::PATHNAMECreated, 0)
                 } else {
                     send_cmd(
                         &mut self.stream,
                         ResultCode::FileNotFound,
                         "No such file or directory",
-                    )
+                    );
+                    (ResultCode::FileNotFound, 0)
                 }
             }
-            Command::Type => send_cmd(
-                &mut self.stream,
-                ResultCode::Ok,
-                "Transfer type changed successfully",
-            ),
+            Command::Type => {
+                send_cmd(
+                    &mut self.stream,
+                    ResultCode::Ok,
+                    "Transfer type changed successfully",
+                );
+                (ResultCode::Ok, 0)
+            }
             Command::Pasv => {
                 if self.data_writer.is_some() {
                     send_cmd(
                         &mut self.stream,
                         ResultCode::DataConnectionAlreadyOpen,
                         "Already listen...",
-                    )
+                    );
+                    (ResultCode::DataConnectionAlreadyOpen, 0)
                 } else {
-                    let port = 43210;
+                    match bind_passive_listener(self.net_config.pasv_port_range) {
+                        Ok(listener) => {
+                            let port = listener.local_addr().map(|a| a.port()).unwrap_or(0);
+                            let ip = self.net_config.public_ip.octets();
+                            send_cmd(
+                                &mut self.stream,
+                                ResultCode::EnteringPassiveMode,
+                                &format!(
+                                    "{},{},{},{},{},{}",
+                                    ip[0], ip[1], ip[2], ip[3], port >> 8, port & 0xff
+                                ),
+                            );
+                            self.active_target = None;
+                            match listener.incoming().next() {
+                                Some(Ok(client)) => {
+                                    self.data_writer = Some(self.wrap_data_stream(client));
+                                    (ResultCode::EnteringPassiveMode, 0)
+                                }
+                                _ => {
+                                    send_cmd(
+                                        &mut self.stream,
+                                        ResultCode::ServiceNotAvailable,
+                                        "issues happen...",
+                                    );
+                                    (ResultCode::ServiceNotAvailable, 0)
+                                }
+                            }
+                        }
+                        Err(_) => {
+                            send_cmd(
+                                &mut self.stream,
+                                ResultCode::ServiceNotAvailable,
+                                "no free port in passive range",
+                            );
+                            (ResultCode::ServiceNotAvailable, 0)
+                        }
+                    }
+                }
+            },
+            Command::Epsv => {
+                if self.data_writer.is_some() {
                     send_cmd(
                         &mut self.stream,
-                        ResultCode::EnteringPassiveMode,
-                        &format!("127,0,0,1, {}, {}", port >> 8, port & 0xff),
+                        ResultCode::DataConnectionAlreadyOpen,
+                        "Already listen...",
                     );
-                    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
-                    let listener = TcpListener::bind(&addr).unwrap();
-                    match listener.incoming().next() {
-                        Some(Ok(client)) => {
-                            self.data_writer = Some(client);
+                    (ResultCode::DataConnectionAlreadyOpen, 0)
+                } else {
+                    match bind_passive_listener(self.net_config.pasv_port_range) {
+                        Ok(listener) => {
+                            let port = listener.local_addr().map(|a| a.port()).unwrap_or(0);
+                            send_cmd(
+                                &mut self.stream,
+                                ResultCode::EnteringPassiveMode,
+                                &format!("Entering Extended Passive Mode (|||{}|)", port),
+                            );
+                            self.active_target = None;
+                            match listener.incoming().next() {
+                                Some(Ok(client)) => {
+                                    self.data_writer = Some(self.wrap_data_stream(client));
+                                    (ResultCode::EnteringPassiveMode, 0)
+                                }
+                                _ => {
+                                    send_cmd(
+                                        &mut self.stream,
+                                        ResultCode::ServiceNotAvailable,
+                                        "issues happen...",
+                                    );
+                                    (ResultCode::ServiceNotAvailable, 0)
+                                }
+                            }
                         }
-                        _ => {
+                        Err(_) => {
                             send_cmd(
                                 &mut self.stream,
                                 ResultCode::ServiceNotAvailable,
-                                "issues happen...",
+                                "no free port in passive range",
                             );
+                            (ResultCode::ServiceNotAvailable, 0)
                         }
                     }
                 }
             },
+            Command::Port(addr) => {
+                if let Some(addr) = addr {
+                    self.data_writer = None;
+                    self.active_target = Some(addr);
+                    send_cmd(&mut self.stream, ResultCode::Ok, "PORT command successful");
+                    (ResultCode::Ok, 0)
+                } else {
+                    send_cmd(
+                        &mut self.stream,
+                        ResultCode::InvalidParameterOrArgument,
+                        "Invalid PORT argument",
+                    );
+                    (ResultCode::InvalidParameterOrArgument, 0)
+                }
+            },
+            Command::Eprt(addr) => {
+                if let Some(addr) = addr {
+                    self.data_writer = None;
+                    self.active_target = Some(addr);
+                    send_cmd(&mut self.stream, ResultCode::Ok, "EPRT command successful");
+                    (ResultCode::Ok, 0)
+                } else {
+                    send_cmd(
+                        &mut self.stream,
+                        ResultCode::InvalidParameterOrArgument,
+                        "Invalid EPRT argument",
+                    );
+                    (ResultCode::InvalidParameterOrArgument, 0)
+                }
+            },
+            Command::Rest(offset) => {
+                self.restart_offset = Some(offset);
+                send_cmd(
+                    &mut self.stream,
+                    ResultCode::RequestedFileActionPendingFurtherInformation,
+                    &format!("Restarting at {}. Send RETR or STOR to initiate transfer", offset),
+                );
+                (ResultCode::RequestedFileActionPendingFurtherInformation, 0)
+            },
             Command::List(path) => {
 
-                let server_root = env::current_dir().unwrap();
+                let server_root = self.root.clone();
                 let path = self.cwd.join(&path);
                 let real_path = self.complete_path(path, &server_root);
+                let mut result_code = ResultCode::ConnectionClosed;
+
+                self.ensure_data_connection();
 
                 if let Some(ref mut data_writer) = self.data_writer {
 
@@ -328,6 +1058,7 @@ impl Client {
                             ResultCode::DataConnectionAlreadyOpen,
                             "Starting to list directory...",
                         );
+                        result_code = ResultCode::DataConnectionAlreadyOpen;
 
                         let mut out = String::new();
                         if path.is_dir() {
@@ -346,6 +1077,72 @@ impl Client {
                             ResultCode::DataConnectionAlreadyOpen,
                             "No such file or directory...",
                         );
+                        result_code = ResultCode::DataConnectionAlreadyOpen;
+                    }
+                } else {
+                    send_cmd(
+                        &mut self.stream,
+                        ResultCode::ConnectionClosed,
+                        "No opened data connection",
+                    );
+                }
+
+                if self.data_writer.is_some() {
+                    self.data_writer = None;
+                    send_cmd(
+                        &mut self.stream,
+                        ResultCode::ClosingDataConnection,
+                        "Transfer done",
+                    );
+                    result_code = ResultCode::ClosingDataConnection;
+                }
+                (result_code, 0)
+            },
+            Command::Mlsd(path) => {
+
+                let server_root = self.root.clone();
+                let path = self.cwd.join(&path);
+                let real_path = self.complete_path(path, &server_root);
+                let mut result_code = ResultCode::ConnectionClosed;
+
+                self.ensure_data_connection();
+
+                if let Some(ref mut data_writer) = self.data_writer {
+
+                    if let Ok(path) = real_path {
+                        send_cmd(
+                            &mut self.stream,
+                            ResultCode::DataConnectionAlreadyOpen,
+                            "Starting to list directory...",
+                        );
+                        result_code = ResultCode::DataConnectionAlreadyOpen;
+
+                        let mut out = String::new();
+                        if path.is_dir() {
+                            add_mlsx_info(path.clone(), "cdir", &mut out);
+                            let pdir = if path == server_root {
+                                server_root.clone()
+                            } else {
+                                path.parent().map(Path::to_path_buf).unwrap_or_else(|| server_root.clone())
+                            };
+                            add_mlsx_info(pdir, "pdir", &mut out);
+                            for entry in read_dir(&path).unwrap() {
+                                if let Ok(entry) = entry {
+                                    let kind = if entry.path().is_dir() { "dir" } else { "file" };
+                                    add_mlsx_info(entry.path(), kind, &mut out);
+                                }
+                            }
+                            send_data(data_writer, &out);
+                        } else {
+                            add_mlsx_info(path, "file", &mut out);
+                        }
+                    } else {
+                        send_cmd(
+                            &mut self.stream,
+                            ResultCode::DataConnectionAlreadyOpen,
+                            "No such file or directory...",
+                        );
+                        result_code = ResultCode::DataConnectionAlreadyOpen;
                     }
                 } else {
                     send_cmd(
@@ -362,30 +1159,142 @@ impl Client {
                         ResultCode::ClosingDataConnection,
                         "Transfer done",
                     );
+                    result_code = ResultCode::ClosingDataConnection;
+                }
+                (result_code, 0)
+            },
+            Command::Mlst(path) => {
+                let server_root = self.root.clone();
+                let path = self.cwd.join(&path);
+                if let Ok(real_path) = self.complete_path(path, &server_root) {
+                    let kind = if real_path.is_dir() { "cdir" } else { "file" };
+                    let mut fact = String::new();
+                    add_mlsx_info(real_path, kind, &mut fact);
+                    let fact = fact.trim_end_matches("\r\n");
+                    send_cmd(
+                        &mut self.stream,
+                        ResultCode::RequestedFileActionOkay,
+                        fact,
+                    );
+                    (ResultCode::RequestedFileActionOkay, 0)
+                } else {
+                    send_cmd(
+                        &mut self.stream,
+                        ResultCode::FileNotFound,
+                        "No such file or directory",
+                    );
+                    (ResultCode::FileNotFound, 0)
                 }
             },
-            Command::Cwd(directory) => self.cwd(directory),
+            Command::Cwd(directory) => (self.cwd(directory), 0),
             Command::CdUp => {
                 if let Some(path) = self.cwd.parent().map(Path::to_path_buf) {
                     self.cwd = path;
                 }
                 send_cmd(&mut self.stream, ResultCode::Ok, "Done");
+                (ResultCode::Ok, 0)
             },
-            Command::Mkd(path) => {
-                self.mkd(path);
+            Command::Mkd(path) => (self.mkd(path), 0),
+            Command::Rmd(path) => (self.rmd(path), 0),
+            Command::Retr(path) => match path {
+                Some(path) => self.retr(path),
+                None => {
+                    send_cmd(
+                        &mut self.stream,
+                        ResultCode::InvalidParameterOrArgument,
+                        "Missing file name",
+                    );
+                    (ResultCode::InvalidParameterOrArgument, 0)
+                }
             },
-            Command::Rmd(path) => {
-                self.rmd(path);
+            Command::Stor(path) => match path {
+                Some(path) => self.stor(path),
+                None => {
+                    send_cmd(
+                        &mut self.stream,
+                        ResultCode::InvalidParameterOrArgument,
+                        "Missing file name",
+                    );
+                    (ResultCode::InvalidParameterOrArgument, 0)
+                }
             },
-            Command::Unknown(_s) => send_cmd(
-                &mut self.stream,
-                ResultCode::CommandNotImplemented,
-                "Not implemented",
-            ),
-        }
+            Command::Dele(path) => match path {
+                Some(path) => (self.dele(path), 0),
+                None => {
+                    send_cmd(
+                        &mut self.stream,
+                        ResultCode::InvalidParameterOrArgument,
+                        "Missing file name",
+                    );
+                    (ResultCode::InvalidParameterOrArgument, 0)
+                }
+            },
+            Command::Pbsz(_size) => {
+                if self.tls_active {
+                    send_cmd(&mut self.stream, ResultCode::Ok, "PBSZ=0");
+                    (ResultCode::Ok, 0)
+                } else {
+                    send_cmd(&mut self.stream, ResultCode::BadSequenceOfCommands, "AUTH TLS required first");
+                    (ResultCode::BadSequenceOfCommands, 0)
+                }
+            },
+            Command::Prot(level) => {
+                if !self.tls_active {
+                    send_cmd(&mut self.stream, ResultCode::BadSequenceOfCommands, "AUTH TLS required first");
+                    (ResultCode::BadSequenceOfCommands, 0)
+                } else {
+                    match level.as_str() {
+                        "P" => {
+                            self.protected = true;
+                            send_cmd(&mut self.stream, ResultCode::Ok, "Protection level set to Private");
+                            (ResultCode::Ok, 0)
+                        }
+                        "C" => {
+                            self.protected = false;
+                            send_cmd(&mut self.stream, ResultCode::Ok, "Protection level set to Clear");
+                            (ResultCode::Ok, 0)
+                        }
+                        _ => {
+                            send_cmd(
+                                &mut self.stream,
+                                ResultCode::CommandNotImplementedForThatParameter,
+                                "Unsupported protection level",
+                            );
+                            (ResultCode::CommandNotImplementedForThatParameter, 0)
+                        }
+                    }
+                }
+            },
+            Command::Unknown(_s) => {
+                send_cmd(
+                    &mut self.stream,
+                    ResultCode::CommandNotImplemented,
+                    "Not implemented",
+                );
+                (ResultCode::CommandNotImplemented, 0)
+            }
+        };
+
+        self.audit.log_result(self.peer, &summary, code, bytes);
     }
 }
 
+/// Returns `true` for commands that need a logged-in client. Only the
+/// TLS/auth handshake (`AUTH`, `USER`, `PASS`, `PBSZ`, `PROT`) plus `NOOP`
+/// and `SYST` are usable before login.
+fn requires_login(cmd: &Command) -> bool {
+    !matches!(
+        cmd,
+        Command::Auth
+            | Command::User(_)
+            | Command::Pass(_)
+            | Command::Noop
+            | Command::Syst
+            | Command::Pbsz(_)
+            | Command::Prot(_)
+    )
+}
+
 fn to_uppercase(data: &mut [u8]) {
     for byte in data {
         if *byte >= 'a' as u8 && *byte <= 'z' as u8 {
@@ -394,18 +1303,17 @@ fn to_uppercase(data: &mut [u8]) {
     }
 }
 
-fn send_cmd(stream: &mut TcpStream, code: ResultCode, message: &str) {
+fn send_cmd<W: Write + ?Sized>(stream: &mut W, code: ResultCode, message: &str) {
     let msg = if message.is_empty() {
         format!("{}\r\n", code as u32)
     } else {
         format!("{} {}\r\n", code as u32, message)
     };
 
-    println!("<==== {}", msg);
     write!(stream, "{}", msg).unwrap()
 }
 
-fn read_all_message(stream: &mut TcpStream) -> Vec<u8> {
+fn read_all_message<R: Read + ?Sized>(stream: &mut R) -> Vec<u8> {
     let buf = &mut [0; 1];
     let mut out = Vec::with_capacity(100);
 
@@ -428,25 +1336,37 @@ fn read_all_message(stream: &mut TcpStream) -> Vec<u8> {
     }
 }
 
-fn handle_client(mut stream: TcpStream) {
-    println!("new client connected!");
+fn handle_client(
+    mut stream: TcpStream,
+    tls_config: Option<Arc<ServerConfig>>,
+    credentials: Arc<CredentialStore>,
+    server_root: PathBuf,
+    audit: Arc<AuditLog>,
+    net_config: Arc<NetConfig>,
+) {
+    let peer = stream.peer_addr().ok();
+    if let Some(peer) = peer {
+        audit.log_connect(peer);
+    }
     send_cmd(
         &mut stream,
         ResultCode::ServiceReadyForNewUser,
         "Welcome to this FTP server!",
     );
-    let mut client = Client::new(stream);
+    let mut client = Client::new(stream, tls_config, credentials, server_root, audit.clone(), net_config);
     loop {
         let data = read_all_message(&mut client.stream);
         if data.is_empty() {
-            println!("client disconnected...");
+            if let Some(peer) = peer {
+                audit.log_disconnect(peer);
+            }
             break;
         }
         client.handle_cmd(Command::new(data).unwrap());
     }
 }
 
-fn send_data(stream: &mut TcpStream, s: &str) {
+fn send_data<W: Write + ?Sized>(stream: &mut W, s: &str) {
     write!(stream, "{}", s).unwrap();
 }
 
@@ -494,13 +1414,99 @@ fn add_file_info(path: PathBuf, out: &mut String) {
     println!("==> {:?}", &file_str);
 }
 
+/// Formats one RFC 3659 fact line (`MLSD`/`MLST`) for `path`, tagging it with
+/// `kind` (`file`, `dir`, `cdir`, or `pdir`). `modify` is always rendered in
+/// UTC, unlike the local-time `add_file_info` listing.
+fn add_mlsx_info(path: PathBuf, kind: &str, out: &mut String) {
+    let meta = match ::std::fs::metadata(&path) {
+        Ok(meta) => meta,
+        _ => return,
+    };
+
+    let (time, file_size) = get_file_info(&meta);
+    let modify = time::at_utc(time.to_timespec());
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => ".",
+    };
+    let perm = if meta.permissions().readonly() {
+        "r"
+    } else {
+        "rw"
+    };
+
+    let fact_line = format!(
+        "type={kind};size={size};modify={year:04}{month:02}{day:02}T{hour:02}{min:02}{sec:02};perm={perm}; {name}\r\n",
+        kind = kind,
+        size = file_size,
+        year = modify.tm_year + 1900,
+        month = modify.tm_mon + 1,
+        day = modify.tm_mday,
+        hour = modify.tm_hour,
+        min = modify.tm_min,
+        sec = modify.tm_sec,
+        perm = perm,
+        name = name,
+    );
+    out.push_str(&fact_line);
+}
+
+/// Loads `cert.pem`/`key.pem` from the working directory, if present, to
+/// enable `AUTH TLS`. Returns `None` when no certificate is configured so
+/// the server still runs in plain-FTP-only mode.
+fn load_tls_config() -> Option<Arc<ServerConfig>> {
+    let cert_file = File::open("cert.pem").ok()?;
+    let key_file = File::open("key.pem").ok()?;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+        .ok()?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(key_file)).ok()?;
+    if keys.is_empty() {
+        return None;
+    }
+    let key = PrivateKey(keys.remove(0));
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .expect("bad certificate/key pair");
+    Some(Arc::new(config))
+}
+
 fn main() {
     let listener = TcpListener::bind("0.0.0.0:1234").expect("Coundn't bind this address...");
     println!("Waiting for clients to connect...");
 
+    let tls_config = load_tls_config();
+    if tls_config.is_some() {
+        println!("AUTH TLS enabled (cert.pem/key.pem loaded)");
+    }
+
+    let credentials = Arc::new(CredentialStore::load(Path::new("credentials.toml")));
+    let server_root = env::current_dir().expect("Coundn't read the current directory");
+
+    let log_path = env::var("SYN_FTP_LOG_FILE").unwrap_or_else(|_| "syn_ftp_audit.log".to_owned());
+    let audit = Arc::new(
+        AuditLog::open(Path::new(&log_path), Verbosity::from_env())
+            .expect("Coundn't open the audit log file"),
+    );
+
+    let net_config = Arc::new(NetConfig::from_env());
+
     for stream in listener.incoming() {
         if let Ok(stream) = stream {
-            thread::spawn(move || handle_client(stream));
+            let tls_config = tls_config.clone();
+            let credentials = credentials.clone();
+            let server_root = server_root.clone();
+            let audit = audit.clone();
+            let net_config = net_config.clone();
+            thread::spawn(move || {
+                handle_client(stream, tls_config, credentials, server_root, audit, net_config)
+            });
         } else {
             println!("A client tried to connect...")
         }